@@ -2,31 +2,147 @@
 
 extern crate num;
 extern crate image;
-extern crate crossbeam;
+extern crate rayon;
 
 use num::Complex;
 use std::str::FromStr;
 use image::ColorType;
 use image::png::PNGEncoder;
 use std::fs::File;
-use std::io::Write;
 use std::num::ParseFloatError;
+use std::num::ParseIntError;
+use std::fmt;
+use rayon::prelude::*;
 
-fn escapes(c: Complex<f64>, limit: u32) -> Option<u32> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FractalKind {
+    Mandelbrot,
+    Mandelbrot3,
+    BurningShip
+}
+
+impl FromStr for FractalKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mandelbrot" => Ok(FractalKind::Mandelbrot),
+            "mandelbrot3" => Ok(FractalKind::Mandelbrot3),
+            "burning_ship" => Ok(FractalKind::BurningShip),
+            _ => Err(format!("unknown fractal kind '{}' (expected one of: mandelbrot, mandelbrot3, burning_ship)", s))
+        }
+    }
+}
+
+#[test]
+fn test_fractal_kind_from_str() {
+    assert_eq!(FractalKind::from_str("mandelbrot"), Ok(FractalKind::Mandelbrot));
+    assert_eq!(FractalKind::from_str("mandelbrot3"), Ok(FractalKind::Mandelbrot3));
+    assert_eq!(FractalKind::from_str("burning_ship"), Ok(FractalKind::BurningShip));
+    assert_eq!(
+        FractalKind::from_str("julia"),
+        Err("unknown fractal kind 'julia' (expected one of: mandelbrot, mandelbrot3, burning_ship)".to_string()));
+}
+
+fn step(kind: FractalKind, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+    match kind {
+        FractalKind::Mandelbrot => z * z + c,
+        FractalKind::Mandelbrot3 => z * z * z + c,
+        FractalKind::BurningShip => {
+            let folded = Complex { re: z.re.abs(), im: z.im.abs() };
+            folded * folded + c
+        }
+    }
+}
+
+#[test]
+fn test_step() {
+    let c = Complex { re: 1.0, im: 1.0 };
+    let z = Complex { re: 2.0, im: -3.0 };
+    assert_eq!(step(FractalKind::Mandelbrot, z, c), z * z + c);
+    assert_eq!(step(FractalKind::Mandelbrot3, z, c), z * z * z + c);
+    assert_eq!(
+        step(FractalKind::BurningShip, z, c),
+        Complex { re: 2.0, im: 3.0 } * Complex { re: 2.0, im: 3.0 } + c);
+}
+
+fn escapes(kind: FractalKind, c: Complex<f64>, limit: u32) -> Option<(u32, Complex<f64>)> {
     let mut z = Complex { re: 0.0, im: 0.0 };
     for i in 0..limit {
-        z = z * z + c;
+        z = step(kind, z, c);
         if z.norm_sqr() > 4.0 {
-            return Some(i);
+            return Some((i, z));
         }
     }
     None
 }
 
+/// Converts an escape-time result into a fractional iteration count, which
+/// removes the banding that comes from coloring by integer iteration alone.
+/// See https://linas.org/art-gallery/escape/escape.html for the derivation.
+fn smooth_iteration_count(n: u32, z: Complex<f64>) -> f64 {
+    n as f64 + 1.0 - (z.norm_sqr().ln() / 2.0).ln() / 2.0f64.ln()
+}
+
+#[test]
+fn test_smooth_iteration_count() {
+    // |z| == e makes ln(ln|z|) == ln(1) == 0, so nu reduces to n + 1 exactly.
+    let z = Complex { re: std::f64::consts::E, im: 0.0 };
+    assert_eq!(smooth_iteration_count(0, z), 1.0);
+    assert_eq!(smooth_iteration_count(4, z), 5.0);
+}
+
+fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
+    let h = h.rem_euclid(360.0);
+    let c = v * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = v - c;
+    let (r, g, b) = match h as u32 {
+        0..=59 => (c, x, 0.0),
+        60..=119 => (x, c, 0.0),
+        120..=179 => (0.0, c, x),
+        180..=239 => (0.0, x, c),
+        240..=299 => (x, 0.0, c),
+        _ => (c, 0.0, x)
+    };
+    (((r + m) * 255.0) as u8, ((g + m) * 255.0) as u8, ((b + m) * 255.0) as u8)
+}
+
+#[test]
+fn test_hsv_to_rgb() {
+    assert_eq!(hsv_to_rgb(0.0, 1.0, 1.0), (255, 0, 0));
+    assert_eq!(hsv_to_rgb(120.0, 1.0, 1.0), (0, 255, 0));
+    assert_eq!(hsv_to_rgb(240.0, 1.0, 1.0), (0, 0, 255));
+    assert_eq!(hsv_to_rgb(0.0, 0.0, 0.0), (0, 0, 0));
+    // hue wraps cyclically
+    assert_eq!(hsv_to_rgb(360.0, 1.0, 1.0), hsv_to_rgb(0.0, 1.0, 1.0));
+}
+
+/// Maps an escape-time result to an RGB color: black for points that never
+/// escape, and a cyclic hue driven by the smooth iteration count otherwise.
+fn escape_color(escape: Option<(u32, Complex<f64>)>) -> (u8, u8, u8) {
+    match escape {
+        None => (0, 0, 0),
+        Some((n, z)) => {
+            let nu = smooth_iteration_count(n, z);
+            hsv_to_rgb(nu * 10.0, 0.7, 1.0)
+        }
+    }
+}
+
+#[test]
+fn test_escape_color() {
+    assert_eq!(escape_color(None), (0, 0, 0));
+
+    let z = Complex { re: std::f64::consts::E, im: 0.0 };
+    let nu = smooth_iteration_count(0, z);
+    assert_eq!(escape_color(Some((0, z))), hsv_to_rgb(nu * 10.0, 0.7, 1.0));
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum ParsePairError<T> {
     ParseElementError(T),
-    NoDelimiter
+    NoDelimiter(char)
 }
 
 impl<T> From<T> for ParsePairError<T>  {
@@ -35,9 +151,18 @@ impl<T> From<T> for ParsePairError<T>  {
     }
 }
 
+impl<T: fmt::Display> fmt::Display for ParsePairError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParsePairError::ParseElementError(e) => write!(f, "{}", e),
+            ParsePairError::NoDelimiter(separator) => write!(f, "no '{}'-delimiter found", separator)
+        }
+    }
+}
+
 fn parse_pair<T: FromStr>(s: &str, separator: char) -> Result<(T, T), ParsePairError<T::Err>> {
     match s.find(separator) {
-        None => Err(ParsePairError::NoDelimiter),
+        None => Err(ParsePairError::NoDelimiter(separator)),
         Some(index) => {
             let e1 = T::from_str(&s[..index])?;
             let e2 = T::from_str(&s[index + 1..])?;
@@ -48,15 +173,16 @@ fn parse_pair<T: FromStr>(s: &str, separator: char) -> Result<(T, T), ParsePairE
 
 #[test]
 fn test_parse_pair() {
-    assert_eq!(parse_pair::<i32>("", ','), None);
-    assert_eq!(parse_pair::<i32>("10", ','), None);
-    assert_eq!(parse_pair::<i32>(",10", ','), None);
-    assert_eq!(parse_pair::<i32>("10,20", ','), Some((10, 20)));
-    assert_eq!(parse_pair::<i32>("10,20xy", ','), None);
-    assert_eq!(parse_pair::<f64>("0.5x", ','), None);
-    assert_eq!(parse_pair::<f32>("0.5x1.5", 'x'), Some((0.5, 1.5)));
+    assert_eq!(parse_pair::<i32>("", ','), Err(ParsePairError::NoDelimiter(',')));
+    assert_eq!(parse_pair::<i32>("10", ','), Err(ParsePairError::NoDelimiter(',')));
+    assert!(parse_pair::<i32>(",10", ',').is_err());
+    assert_eq!(parse_pair::<i32>("10,20", ','), Ok((10, 20)));
+    assert!(parse_pair::<i32>("10,20xy", ',').is_err());
+    assert!(parse_pair::<f64>("0.5x", ',').is_err());
+    assert_eq!(parse_pair::<f32>("0.5x1.5", 'x'), Ok((0.5, 1.5)));
 }
 
+#[derive(Debug, PartialEq)]
 struct Point {
     x: f64,
     y: f64
@@ -72,97 +198,192 @@ impl FromStr for Point {
     }
 }
 
-fn pixel_to_point((lower_bound, upper_bound): (usize, usize),
-                  (p0, p1): (usize, usize),
-                  upper_left: &Point,
-                  lower_right: &Point) -> Point
+/// Maps a (possibly fractional) pixel coordinate to a point in the complex
+/// plane. Takes a fractional coordinate, rather than the pixel's integer
+/// indices, so a supersampling renderer can sample several points per pixel.
+fn pixel_to_point_frac((lower_bound, upper_bound): (usize, usize),
+                       (x, y): (f64, f64),
+                       upper_left: &Point,
+                       lower_right: &Point) -> Point
 {
     let (width, height) = (lower_right.x - upper_left.x,
                            upper_left.y - lower_right.y);
     Point {
-        x: upper_left.x + p0 as f64 * width / lower_bound as f64,
-        y: upper_left.y - p1 as f64 * height / upper_bound as f64
+        x: upper_left.x + x * width / lower_bound as f64,
+        y: upper_left.y - y * height / upper_bound as f64
     }
 }
 
 #[test]
-fn test_pixel_to_point() {
+fn test_pixel_to_point_frac() {
+    let upper_left = Point { x: -1.0, y: 1.0 };
+    let lower_right = Point { x: 1.0, y: -1.0 };
     assert_eq!(
-    pixel_to_point((100, 100), (25, 75), (-1.0, 1.0), (1.0, -1.0)),
-    (-0.5, -0.5));
+        pixel_to_point_frac((100, 100), (25.0, 75.0), &upper_left, &lower_right),
+        Point { x: -0.5, y: -0.5 });
 }
 
-fn render(pixels: &mut [u8], bounds: (usize, usize), upper_left: &Point, lower_right: &Point) {
-    assert!(pixels.len() == bounds.0 * bounds.1);
+fn render(kind: FractalKind, samples: u32, pixels: &mut [u8], bounds: (usize, usize), upper_left: &Point, lower_right: &Point) {
+    assert!(pixels.len() == bounds.0 * bounds.1 * 3);
+
+    let sample_count = samples * samples;
 
-    for r in 0..bounds.1 {
+    pixels.par_chunks_mut(bounds.0 * 3).enumerate().for_each(|(r, row)| {
         for c in 0..bounds.0 {
-            let point = pixel_to_point(bounds, (c, r), upper_left, lower_right);
-            pixels[r * bounds.0 + c] =
-                match escapes(Complex { re: point.x, im: point.y }, 255) {
-                    None => 0,
-                    Some(count) => 255 - count as u8
+            let (mut red_sum, mut green_sum, mut blue_sum) = (0u32, 0u32, 0u32);
+
+            for sy in 0..samples {
+                for sx in 0..samples {
+                    let x = c as f64 + (sx as f64 + 0.5) / samples as f64;
+                    let y = r as f64 + (sy as f64 + 0.5) / samples as f64;
+                    let point = pixel_to_point_frac(bounds, (x, y), upper_left, lower_right);
+                    let (red, green, blue) = escape_color(escapes(kind, Complex { re: point.x, im: point.y }, 255));
+                    red_sum += red as u32;
+                    green_sum += green as u32;
+                    blue_sum += blue as u32;
                 }
+            }
+
+            let offset = c * 3;
+            row[offset] = (red_sum / sample_count) as u8;
+            row[offset + 1] = (green_sum / sample_count) as u8;
+            row[offset + 2] = (blue_sum / sample_count) as u8;
         }
-    }
+    });
 }
 
 fn write_bitmap(filename: &str, pixels: &[u8], bounds: (usize, usize)) -> Result<(), std::io::Error>
 {
     let output = File::create(filename)?;
     let encoder = PNGEncoder::new(output);
-    encoder.encode(&pixels, bounds.0 as u32, bounds.1 as u32, ColorType::Gray(8))?;
+    encoder.encode(&pixels, bounds.0 as u32, bounds.1 as u32, ColorType::RGB(8))?;
     Ok(())
 }
 
-fn main() {
+#[derive(Debug)]
+enum AppError {
+    Usage,
+    InvalidArgument { name: &'static str, message: String },
+    Io(std::io::Error)
+}
+
+impl AppError {
+    /// A distinct nonzero status code per error category, so scripts can
+    /// tell a usage mistake from a bad argument from an I/O failure.
+    fn exit_code(&self) -> i32 {
+        match *self {
+            AppError::Usage => 1,
+            AppError::InvalidArgument { .. } => 2,
+            AppError::Io(_) => 3
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::Usage => {
+                writeln!(f, "Usage: mandelbrot <file> <pixels> <upperleft> <lowerright> <threads> <fractal> [samples]")?;
+                writeln!(f, "       <fractal> is one of: mandelbrot, mandelbrot3, burning_ship")?;
+                write!(f, "       [samples] is the supersampling factor per axis, default 1")
+            }
+            AppError::InvalidArgument { name, message } => write!(f, "error parsing <{}>: {}", name, message),
+            AppError::Io(e) => write!(f, "error writing PNG file: {}", e)
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Io(e)
+    }
+}
+
+#[test]
+fn test_app_error_exit_code() {
+    assert_eq!(AppError::Usage.exit_code(), 1);
+    assert_eq!(AppError::InvalidArgument { name: "threads", message: "bad".to_string() }.exit_code(), 2);
+    assert_eq!(
+        AppError::Io(std::io::Error::new(std::io::ErrorKind::Other, "disk full")).exit_code(),
+        3);
+}
+
+#[test]
+fn test_app_error_display() {
+    assert_eq!(
+        AppError::InvalidArgument { name: "lowerright", message: "no ','-delimiter found".to_string() }.to_string(),
+        "error parsing <lowerright>: no ','-delimiter found");
+    assert!(AppError::Usage.to_string().starts_with("Usage: mandelbrot"));
+}
+
+fn run() -> Result<(), AppError> {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 6 {
-        writeln!(std::io::stderr(), "Usage: mandelbrot <file> <pixels> <upperleft> <lowerright> <threads>").unwrap();
-        std::process::exit(1)
+    if args.len() != 7 && args.len() != 8 {
+        return Err(AppError::Usage);
     }
 
-    let bounds = parse_pair(&args[2], 'x').expect("error parsing image dimensions");
+    let bounds: (usize, usize) = parse_pair(&args[2], 'x')
+        .map_err(|e| AppError::InvalidArgument { name: "pixels", message: e.to_string() })?;
+
+    if bounds.0 == 0 || bounds.1 == 0 {
+        return Err(AppError::InvalidArgument { name: "pixels", message: "width and height must be at least 1".to_string() });
+    }
 
     let upper_left = {
-        let p = parse_pair(&args[3], ',').expect("error parsing upper left corner point");
+        let p = parse_pair(&args[3], ',')
+            .map_err(|e| AppError::InvalidArgument { name: "upperleft", message: e.to_string() })?;
         Point { x: p.0, y: p.1 }
     };
 
     let lower_right = {
-        let p = parse_pair(&args[4], ',').expect("error parsing lower right corner point");
+        let p = parse_pair(&args[4], ',')
+            .map_err(|e| AppError::InvalidArgument { name: "lowerright", message: e.to_string() })?;
         Point { x: p.0, y: p.1 }
     };
 
-    let mut pixels = vec![0; bounds.0 * bounds.1];
-
-    let threads = usize::from_str(&args[5]).expect("error parsing thread count");
-
-    if threads > 1 {
-        println!("Parallel using {} threads.", threads);
-        let band_rows = bounds.1 / threads + 1;
-        let bands: Vec<&mut [u8]> = pixels.chunks_mut(band_rows * bounds.0).collect();
-        crossbeam::scope(|scope| {
-            for (i, band) in bands.into_iter().enumerate() {
-                let top = band_rows * i;
-                let height = band.len() / bounds.0;
-                let band_bounds = (bounds.0, height);
-                let band_upper_left = pixel_to_point(bounds, (0, top), &upper_left, &lower_right);
-                let band_lower_right = pixel_to_point(bounds, (bounds.0, top + height), &upper_left, &lower_right);
-
-                scope.spawn(move || {
-                    println!(">>> Thread #{}, {} pixels", i, band.len());
-                    render(band, band_bounds, &band_upper_left, &band_lower_right);
-                    println!("<<< Thread #{}", i)
-                });
-            }
-        });
+    let mut pixels = vec![0; bounds.0 * bounds.1 * 3];
+
+    let threads = usize::from_str(&args[5])
+        .map_err(|e: ParseIntError| AppError::InvalidArgument { name: "threads", message: e.to_string() })?;
+
+    let kind = FractalKind::from_str(&args[6])
+        .map_err(|message| AppError::InvalidArgument { name: "fractal", message })?;
+
+    let samples = if args.len() == 8 {
+        let samples = u32::from_str(&args[7])
+            .map_err(|e: ParseIntError| AppError::InvalidArgument { name: "samples", message: e.to_string() })?;
+        if samples == 0 {
+            return Err(AppError::InvalidArgument { name: "samples", message: "must be at least 1".to_string() });
+        }
+        // render() sums up to 255 per sample into a u32 accumulator, so that
+        // has to fit as well as samples*samples itself.
+        samples.checked_mul(samples)
+            .and_then(|sample_count| sample_count.checked_mul(255))
+            .ok_or_else(|| AppError::InvalidArgument { name: "samples", message: format!("{} is too large: per-pixel color accumulation would overflow", samples) })?;
+        samples
     } else {
-        println!("Sequential.");
-        render(&mut pixels, bounds, &upper_left, &lower_right);
+        1
+    };
+
+    let mut pool_builder = rayon::ThreadPoolBuilder::new();
+    if threads > 0 {
+        pool_builder = pool_builder.num_threads(threads);
     }
+    let pool = pool_builder.build()
+        .map_err(|e| AppError::InvalidArgument { name: "threads", message: e.to_string() })?;
+
+    println!("Rendering using {} threads, {}x supersampling.", pool.current_num_threads(), samples);
+    pool.install(|| render(kind, samples, &mut pixels, bounds, &upper_left, &lower_right));
+
+    write_bitmap(&args[1], &pixels, bounds)?;
 
-    write_bitmap(&args[1], &pixels, bounds).expect("error writing PNG file.");
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}", err);
+        std::process::exit(err.exit_code());
+    }
 }
-    type Err = ();